@@ -1,73 +1,1206 @@
-use std::env;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-fn main() -> std::io::Result<()> {
+use clap::{Parser, ValueEnum};
+use flate2::read::MultiGzDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
 
-    let args: Vec<String> = env::args().collect();
+/// Standard BGZF block size: compress in ~64 KiB chunks of uncompressed
+/// data so each block stays small enough for random access by downstream
+/// tools (samtools/bcftools expect this convention).
+const BGZF_BLOCK_SIZE: usize = 65280;
 
-    let mut vcf_in: Option<String> = None;
-    let mut vcf_out: Option<String> = None;
+/// The 28-byte BGZF end-of-file marker: an empty gzip member with the
+/// standard `BC` extra subfield. Every BGZF stream must end with this so
+/// readers can tell the file wasn't truncated mid-stream.
+const BGZF_EOF: &[u8] = &[
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
 
-    // Should use clap instead
-    for arg in args.iter().skip(1) { // Skip the program name
-        match arg.as_str() {
-            "-i" | "--input" => {
-                // Assuming the next argument is the input file
-                if let Some(index) = args.iter().position(|a| a == arg) {
-                    if let Some(file) = args.get(index + 1) {
-                        vcf_in = Some(file.clone());
+/// A writer that emits BGZF (block-gzip) instead of a single gzip stream:
+/// each call to `flush_block` produces one independent gzip member carrying
+/// a `BC` extra subfield with the member's total on-disk size minus one.
+struct BgzfWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    fn new(inner: W) -> Self {
+        BgzfWriter {
+            inner,
+            buf: Vec::with_capacity(BGZF_BLOCK_SIZE),
+        }
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+
+        let mut deflater = DeflateEncoder::new(Vec::new(), Compression::default());
+        deflater.write_all(&self.buf)?;
+        let cdata = deflater.finish()?;
+
+        // Header up through the extra field, with BSIZE (total block size
+        // minus 1) patched in once the full length is known.
+        let mut block = vec![
+            0x1f, 0x8b, 0x08, 0x04, // magic + CM + FLG (FEXTRA)
+            0x00, 0x00, 0x00, 0x00, // MTIME (unset)
+            0x00, 0xff, // XFL, OS (unknown)
+            0x06, 0x00, // XLEN = 6
+            b'B', b'C', // SI1, SI2
+            0x02, 0x00, // SLEN = 2
+            0x00, 0x00, // BSIZE placeholder
+        ];
+        block.extend_from_slice(&cdata);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(self.buf.len() as u32).to_le_bytes());
+
+        let bsize = (block.len() - 1) as u16;
+        block[16..18].copy_from_slice(&bsize.to_le_bytes());
+
+        self.inner.write_all(&block)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.inner.write_all(BGZF_EOF)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        for chunk in data.chunks(BGZF_BLOCK_SIZE) {
+            if self.buf.len() + chunk.len() > BGZF_BLOCK_SIZE {
+                self.flush_block()?;
+            }
+            self.buf.extend_from_slice(chunk);
+            written += chunk.len();
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sniff the first two bytes of `reader` for the gzip magic number
+/// (`1f 8b`) without consuming them, so compression can be detected even
+/// when a `.gz`-named file is piped in under a different name.
+fn looks_gzipped(reader: &mut dyn BufRead) -> io::Result<bool> {
+    let buf = reader.fill_buf()?;
+    Ok(buf.len() >= 2 && buf[0] == 0x1f && buf[1] == 0x8b)
+}
+
+/// Open `path` for reading, transparently unwrapping gzip/BGZF. BGZF is
+/// just a concatenation of independent gzip members, which
+/// `MultiGzDecoder` already decodes as one continuous stream, so no
+/// BGZF-specific reader is needed on this side. `None` or `"-"` means
+/// stdin, the way most Unix filters treat a missing/dash path, so this
+/// tool can sit in a `samtools | ... | bcftools` pipe.
+fn open_input(path: Option<&str>) -> io::Result<Box<dyn BufRead>> {
+    let mut reader: Box<dyn BufRead> = match path {
+        Some(path) if path != "-" => Box::new(BufReader::new(File::open(path)?)),
+        _ => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let gzipped = path.is_some_and(|p| p.ends_with(".gz")) || looks_gzipped(&mut reader)?;
+    if gzipped {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(reader)
+    }
+}
+
+/// Create `path` for writing, wrapping it in a `BgzfWriter` when the
+/// extension says `.gz`. `None` or `"-"` means stdout.
+enum OutputWriter {
+    Plain(Box<dyn Write>),
+    Bgzf(BgzfWriter<Box<dyn Write>>),
+}
+
+impl OutputWriter {
+    fn create(path: Option<&str>, gzipped: bool) -> io::Result<Self> {
+        let sink: Box<dyn Write> = match path {
+            Some(path) if path != "-" => Box::new(BufWriter::new(File::create(path)?)),
+            _ => Box::new(io::stdout()),
+        };
+
+        if gzipped {
+            Ok(OutputWriter::Bgzf(BgzfWriter::new(sink)))
+        } else {
+            Ok(OutputWriter::Plain(sink))
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut w) => w.flush(),
+            OutputWriter::Bgzf(w) => w.finish(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(w) => w.write(data),
+            OutputWriter::Bgzf(w) => w.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(w) => w.flush(),
+            OutputWriter::Bgzf(w) => w.flush(),
+        }
+    }
+}
+
+/// Which on-disk record format to emit. Defaults from the `--output`
+/// extension when not given explicitly.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "lowercase")]
+enum OutputFormat {
+    Vcf,
+    Bcf,
+}
+
+impl OutputFormat {
+    fn from_path(path: &str) -> Self {
+        if path.ends_with(".bcf") {
+            OutputFormat::Bcf
+        } else {
+            OutputFormat::Vcf
+        }
+    }
+}
+
+/// How to dispose of the rewritten records instead of always overwriting
+/// `--output` in place.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "lowercase")]
+enum WriteMode {
+    /// Write the normalized VCF/BCF to `--output` (or create it if missing).
+    Overwrite,
+    /// Write the normalized output to stdout instead of `--output`.
+    Stdout,
+    /// Don't write anything; exit non-zero if any ID would change.
+    Check,
+    /// Don't write anything; print a unified diff of the changed lines.
+    Diff,
+}
+
+/// Command-line interface for the VCF/BCF ID normalizer.
+#[derive(Parser)]
+#[command(about = "Rewrite VCF/BCF record IDs to a chrom:pos:ref:alt convention")]
+struct Cli {
+    /// Input VCF path (plain or gzip/BGZF-compressed text; BCF input is not
+    /// read); omit or pass `-` to read from stdin.
+    #[arg(short = 'i', long = "input")]
+    input: Option<String>,
+
+    /// Output path; omit or pass `-` to write to stdout.
+    #[arg(short = 'o', long = "output")]
+    output: Option<String>,
+
+    /// Output record format; inferred from `--output`'s extension if unset.
+    #[arg(long = "output-format", value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// Emit one output record per ALT allele instead of one joined ID.
+    #[arg(long = "split-multiallelic")]
+    split_multiallelic: bool,
+
+    /// How to dispose of the rewritten records.
+    #[arg(long = "mode", value_enum, default_value = "overwrite")]
+    mode: WriteMode,
+
+    /// Parse and rewrite records across this many worker threads, reading
+    /// the whole input into memory up front instead of streaming it.
+    /// Only applies to `--mode overwrite`/`stdout`.
+    #[arg(short = 't', long = "threads", default_value_t = 1)]
+    threads: usize,
+}
+
+/// The parts of the VCF header this tool needs again once it's past the
+/// `#CHROM` line: the raw text (so BCF can embed it verbatim) and the
+/// contig/FILTER/INFO/FORMAT dictionaries, so records can be stored as
+/// indices into these before encoding.
+struct VcfHeader {
+    raw: String,
+    contigs: Vec<String>,
+    filters: Vec<String>,
+    infos: Vec<String>,
+    formats: Vec<String>,
+}
+
+impl VcfHeader {
+    fn contig_index(&self, chrom: &str) -> io::Result<u32> {
+        dict_index(&self.contigs, chrom, "contig", "CHROM")
+    }
+
+    fn filter_index(&self, filter: &str) -> io::Result<u32> {
+        // PASS is implicit in most VCFs and conventionally dictionary
+        // index 0, even when the header never spells out a ##FILTER line for it.
+        if filter == "PASS" && !self.filters.iter().any(|f| f == "PASS") {
+            return Ok(0);
+        }
+        dict_index(&self.filters, filter, "FILTER", "FILTER")
+    }
+
+    fn info_index(&self, key: &str) -> io::Result<u32> {
+        dict_index(&self.infos, key, "INFO", "INFO")
+    }
+
+    fn format_index(&self, key: &str) -> io::Result<u32> {
+        dict_index(&self.formats, key, "FORMAT", "FORMAT")
+    }
+}
+
+/// Look up `name` in one of `VcfHeader`'s dictionaries, returning a clean
+/// error instead of panicking when a record references an ID the header
+/// never declared.
+fn dict_index(dict: &[String], name: &str, header_kind: &str, column: &str) -> io::Result<u32> {
+    dict.iter()
+        .position(|x| x == name)
+        .map(|i| i as u32)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{column} {name} not declared in a ##{header_kind} header line"),
+            )
+        })
+}
+
+/// Pull the `ID` out of a `##<prefix>=<ID=...>` header line, if `line` is one.
+fn header_entry_id(line: &str, prefix: &str) -> Option<String> {
+    let rest = line.strip_prefix(prefix)?;
+    let id_start = rest.find("ID=")? + 3;
+    Some(rest[id_start..].split([',', '>']).next().unwrap_or("").to_string())
+}
+
+/// Consume header lines (`#`-prefixed) from `reader`, returning the parsed
+/// `VcfHeader` and the first data line, if any, that was read while
+/// looking for the end of the header.
+fn read_header(reader: &mut dyn BufRead) -> io::Result<(VcfHeader, Option<String>)> {
+    let mut raw = String::new();
+    let mut contigs = Vec::new();
+    let mut filters = Vec::new();
+    let mut infos = Vec::new();
+    let mut formats = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if !line.starts_with('#') {
+            let header = VcfHeader { raw, contigs, filters, infos, formats };
+            return Ok((header, Some(line)));
+        }
+
+        collect_header_entry(&line, &mut contigs, &mut filters, &mut infos, &mut formats);
+
+        raw.push_str(&line);
+        raw.push('\n');
+    }
+
+    let header = VcfHeader { raw, contigs, filters, infos, formats };
+    Ok((header, None))
+}
+
+/// Shared by `read_header`/`split_header_blocks`: if `line` is a
+/// `##contig`/`##FILTER`/`##INFO`/`##FORMAT` line, push its `ID` onto the
+/// matching dictionary.
+fn collect_header_entry(
+    line: &str,
+    contigs: &mut Vec<String>,
+    filters: &mut Vec<String>,
+    infos: &mut Vec<String>,
+    formats: &mut Vec<String>,
+) {
+    if let Some(id) = header_entry_id(line, "##contig=<") {
+        contigs.push(id);
+    } else if let Some(id) = header_entry_id(line, "##FILTER=<") {
+        filters.push(id);
+    } else if let Some(id) = header_entry_id(line, "##INFO=<") {
+        infos.push(id);
+    } else if let Some(id) = header_entry_id(line, "##FORMAT=<") {
+        formats.push(id);
+    }
+}
+
+/// Same split as `read_header`, but over an in-memory set of line blocks
+/// (as produced by `bgzf_blocks_to_lines`/`chunk_plain_bytes_to_lines`)
+/// rather than a stream, for the `--threads` parallel path. Returns the
+/// header plus the remaining blocks with header lines stripped out; a
+/// block that was entirely header becomes empty and is dropped so later
+/// block-index bookkeeping doesn't have to special-case it.
+fn split_header_blocks(blocks: Vec<Vec<String>>) -> (VcfHeader, Vec<Vec<String>>) {
+    let mut raw = String::new();
+    let mut contigs = Vec::new();
+    let mut filters = Vec::new();
+    let mut infos = Vec::new();
+    let mut formats = Vec::new();
+    let mut data_blocks = Vec::new();
+    let mut in_header = true;
+
+    for block in blocks {
+        if !in_header {
+            data_blocks.push(block);
+            continue;
+        }
+
+        match block.iter().position(|line| !line.starts_with('#')) {
+            None => {
+                for line in &block {
+                    collect_header_entry(line, &mut contigs, &mut filters, &mut infos, &mut formats);
+                    raw.push_str(line);
+                    raw.push('\n');
+                }
+            }
+            Some(split_at) => {
+                for line in &block[..split_at] {
+                    collect_header_entry(line, &mut contigs, &mut filters, &mut infos, &mut formats);
+                    raw.push_str(line);
+                    raw.push('\n');
+                }
+                in_header = false;
+                let remaining = block[split_at..].to_vec();
+                if !remaining.is_empty() {
+                    data_blocks.push(remaining);
+                }
+            }
+        }
+    }
+
+    (VcfHeader { raw, contigs, filters, infos, formats }, data_blocks)
+}
+
+/// Read the whole input (file or stdin) into memory, needed by the
+/// `--threads` path since block decomposition has to see the full byte
+/// stream up front.
+fn read_all_bytes(path: Option<&str>) -> io::Result<Vec<u8>> {
+    match path {
+        Some(path) if path != "-" => fs::read(path),
+        _ => {
+            let mut buf = Vec::new();
+            io::stdin().read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+    }
+}
+
+/// Split raw BGZF bytes into its independent gzip member slices by walking
+/// each member's `BC` extra subfield for its total on-disk size, the same
+/// bookkeeping `BgzfWriter::flush_block` writes on the way out. Returns
+/// `None` instead of panicking when `raw` isn't actually BGZF (e.g. a
+/// `.vcf.gz` made with plain `gzip`, which has no `BC` subfield), so callers
+/// can fall back to treating it as ordinary gzip.
+fn try_split_bgzf_members(raw: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut members = Vec::new();
+    let mut offset = 0;
+
+    while offset + 12 <= raw.len() {
+        // Magic number, plus the FEXTRA flag that every BGZF member sets.
+        if raw[offset] != 0x1f || raw[offset + 1] != 0x8b || raw[offset + 3] & 0x04 == 0 {
+            return None;
+        }
+
+        let xlen = u16::from_le_bytes([raw[offset + 10], raw[offset + 11]]) as usize;
+        let extra_end = offset + 12 + xlen;
+        if extra_end > raw.len() {
+            return None;
+        }
+        let mut bsize = None;
+        let mut pos = offset + 12;
+        while pos + 4 <= extra_end {
+            let si1 = raw[pos];
+            let si2 = raw[pos + 1];
+            let slen = u16::from_le_bytes([raw[pos + 2], raw[pos + 3]]) as usize;
+            if si1 == b'B' && si2 == b'C' && slen == 2 && pos + 6 <= extra_end {
+                bsize = Some(u16::from_le_bytes([raw[pos + 4], raw[pos + 5]]) as usize);
+            }
+            pos += 4 + slen;
+        }
+
+        let member_len = bsize? + 1;
+        if offset + member_len > raw.len() {
+            return None;
+        }
+        members.push(&raw[offset..offset + member_len]);
+        offset += member_len;
+    }
+
+    Some(members)
+}
+
+fn decompress_gzip_member(member: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    MultiGzDecoder::new(member).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Regroup decompressed BGZF member bytes into line-complete blocks. BGZF
+/// blocks from well-behaved writers already end on a line boundary, but
+/// just in case one doesn't, carry the dangling partial line forward into
+/// the next block (the "light re-chunk" the parallel pipeline needs).
+fn bgzf_blocks_to_lines(members: Vec<Vec<u8>>) -> Vec<Vec<String>> {
+    let mut blocks = Vec::new();
+    let mut carry = String::new();
+
+    for member in members {
+        carry.push_str(&String::from_utf8_lossy(&member));
+        if carry.is_empty() {
+            continue;
+        }
+
+        let cutoff = match carry.rfind('\n') {
+            Some(i) if i == carry.len() - 1 => carry.len(),
+            Some(i) => i + 1,
+            None => continue, // whole member was a partial line; keep carrying
+        };
+
+        let lines: Vec<String> = carry[..cutoff].lines().map(str::to_string).collect();
+        carry = carry[cutoff..].to_string();
+        if !lines.is_empty() {
+            blocks.push(lines);
+        }
+    }
+
+    if !carry.is_empty() {
+        blocks.push(carry.lines().map(str::to_string).collect());
+    }
+
+    blocks
+}
+
+/// Fallback block decomposition for plain (non-BGZF) input: split on byte
+/// count, rounding each block up to the next full line.
+const PLAIN_CHUNK_BYTES: usize = 1 << 20;
+
+fn chunk_plain_bytes_to_lines(data: &[u8]) -> Vec<Vec<String>> {
+    let text = String::from_utf8_lossy(data);
+    let mut blocks = Vec::new();
+    let mut current = Vec::new();
+    let mut current_bytes = 0;
+
+    for line in text.lines() {
+        current_bytes += line.len() + 1;
+        current.push(line.to_string());
+        if current_bytes >= PLAIN_CHUNK_BYTES {
+            blocks.push(std::mem::take(&mut current));
+            current_bytes = 0;
+        }
+    }
+    if !current.is_empty() {
+        blocks.push(current);
+    }
+
+    blocks
+}
+
+/// Load the whole input and decompose it into parse-ready line blocks for
+/// the `--threads` path, transparently handling BGZF the same way
+/// `open_input` does for the streaming path. Gzipped input that isn't
+/// actually BGZF (no per-member `BC` subfield) falls back to decompressing
+/// the whole stream at once and chunking the plain bytes, rather than
+/// assuming the BGZF member layout and panicking.
+fn read_blocks(path: Option<&str>) -> io::Result<Vec<Vec<String>>> {
+    let raw = read_all_bytes(path)?;
+    let gzipped = path.is_some_and(|p| p.ends_with(".gz")) || (raw.len() >= 2 && raw[0] == 0x1f && raw[1] == 0x8b);
+
+    if !gzipped {
+        return Ok(chunk_plain_bytes_to_lines(&raw));
+    }
+
+    match try_split_bgzf_members(&raw) {
+        Some(members) => {
+            let members = members
+                .into_iter()
+                .map(decompress_gzip_member)
+                .collect::<io::Result<Vec<_>>>()?;
+            Ok(bgzf_blocks_to_lines(members))
+        }
+        None => {
+            let decompressed = decompress_gzip_member(&raw)?;
+            Ok(chunk_plain_bytes_to_lines(&decompressed))
+        }
+    }
+}
+
+type BlockResults = Arc<Mutex<Vec<Option<io::Result<Vec<u8>>>>>>;
+
+/// Run `render` over each block on a pool of `threads` workers, then
+/// reassemble the per-block output in original block order. Workers claim
+/// the next unclaimed block index from a shared counter and drop their
+/// result into a slot reserved for that index, which doubles as the
+/// reorder buffer once every worker has finished.
+fn render_blocks_parallel<F>(blocks: Vec<Vec<String>>, threads: usize, render: F) -> io::Result<Vec<u8>>
+where
+    F: Fn(&[String]) -> io::Result<Vec<u8>> + Sync + Send,
+{
+    let n_blocks = blocks.len();
+    let next_index = Arc::new(Mutex::new(0usize));
+    let results: BlockResults = Arc::new(Mutex::new((0..n_blocks).map(|_| None).collect()));
+    let blocks = Arc::new(blocks);
+    let render = Arc::new(render);
+
+    thread::scope(|scope| {
+        for _ in 0..threads.min(n_blocks).max(1) {
+            let next_index = Arc::clone(&next_index);
+            let results = Arc::clone(&results);
+            let blocks = Arc::clone(&blocks);
+            let render = Arc::clone(&render);
+
+            scope.spawn(move || loop {
+                let index = {
+                    let mut next = next_index.lock().unwrap();
+                    if *next >= n_blocks {
+                        return;
+                    }
+                    let index = *next;
+                    *next += 1;
+                    index
+                };
+
+                let rendered = render(&blocks[index]);
+                results.lock().unwrap()[index] = Some(rendered);
+            });
+        }
+    });
+
+    let results = Arc::try_unwrap(results).unwrap().into_inner().unwrap();
+    let mut out = Vec::new();
+    for result in results.into_iter().flatten() {
+        out.extend_from_slice(&result?);
+    }
+    Ok(out)
+}
+
+/// Build a BCF typed-value descriptor: a byte whose low nibble is the type
+/// and whose high nibble is `count` when it fits in 0..=14, else `0xF`
+/// followed by `count` itself as a typed int.
+fn typed_descriptor(ty: u8, count: usize) -> Vec<u8> {
+    if count < 15 {
+        vec![((count as u8) << 4) | ty]
+    } else {
+        let mut out = vec![0xF0 | ty];
+        out.extend_from_slice(&encode_typed_int(count as i32));
+        out
+    }
+}
+
+/// Encode a BCF typed string value: a type descriptor byte (low nibble =
+/// type, high nibble = length when it fits in 0..=14, plus an overflow
+/// length prefix when it doesn't), followed by the string's bytes.
+fn encode_typed_string(s: &str) -> Vec<u8> {
+    const TYPE_STRING: u8 = 0x7;
+    let bytes = s.as_bytes();
+    let mut out = typed_descriptor(TYPE_STRING, bytes.len());
+    out.extend_from_slice(bytes);
+    out
+}
+
+fn encode_typed_int(v: i32) -> Vec<u8> {
+    const TYPE_INT8: u8 = 0x1;
+    const TYPE_INT32: u8 = 0x3;
+    if let Ok(small) = i8::try_from(v) {
+        vec![(1 << 4) | TYPE_INT8, small as u8]
+    } else {
+        let mut out = vec![(1 << 4) | TYPE_INT32];
+        out.extend_from_slice(&v.to_le_bytes());
+        out
+    }
+}
+
+/// Encode a vector of ints (FILTER indices, numeric INFO/FORMAT values) as
+/// one typed value, picking the narrowest width that fits every element.
+fn encode_typed_int_vec(values: &[i32]) -> Vec<u8> {
+    const TYPE_INT8: u8 = 0x1;
+    const TYPE_INT16: u8 = 0x2;
+    const TYPE_INT32: u8 = 0x3;
+
+    let ty = if values.iter().all(|&v| i8::try_from(v).is_ok()) {
+        TYPE_INT8
+    } else if values.iter().all(|&v| i16::try_from(v).is_ok()) {
+        TYPE_INT16
+    } else {
+        TYPE_INT32
+    };
+
+    let mut out = typed_descriptor(ty, values.len());
+    for &v in values {
+        match ty {
+            TYPE_INT8 => out.push(v as i8 as u8),
+            TYPE_INT16 => out.extend_from_slice(&(v as i16).to_le_bytes()),
+            _ => out.extend_from_slice(&v.to_le_bytes()),
+        }
+    }
+    out
+}
+
+fn encode_typed_float_vec(values: &[f32]) -> Vec<u8> {
+    const TYPE_FLOAT: u8 = 0x5;
+    let mut out = typed_descriptor(TYPE_FLOAT, values.len());
+    for &v in values {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    out
+}
+
+/// Encode one INFO value, inferring its BCF type from the text the same way
+/// a VCF reader would: a comma-separated list of ints or floats becomes a
+/// typed number vector, anything else is kept as a string.
+fn encode_info_value(value: &str) -> Vec<u8> {
+    let parts: Vec<&str> = value.split(',').collect();
+    if let Some(ints) = parts.iter().map(|p| p.parse::<i32>().ok()).collect::<Option<Vec<_>>>() {
+        return encode_typed_int_vec(&ints);
+    }
+    if let Some(floats) = parts.iter().map(|p| p.parse::<f32>().ok()).collect::<Option<Vec<_>>>() {
+        return encode_typed_float_vec(&floats);
+    }
+    encode_typed_string(value)
+}
+
+/// Encode one `GT` sample value ("0/1", "1|0", "." ...) into BCF's
+/// `((allele + 1) << 1) | phased` convention, treating a missing allele as -1.
+fn encode_gt_value(gt: &str) -> Vec<i32> {
+    let phased = gt.contains('|');
+    gt.split(['/', '|'])
+        .map(|a| {
+            let allele = if a == "." { -1 } else { a.parse::<i32>().unwrap_or(-1) };
+            ((allele + 1) << 1) | (phased as i32)
+        })
+        .collect()
+}
+
+/// Encode one FORMAT field's values across all samples as a single typed
+/// vector (BCF packs each field's per-sample values back-to-back). `GT` gets
+/// its own allele encoding; other fields are inferred as ints/floats/strings
+/// the same way `encode_info_value` does, assuming one value per sample
+/// (multi-value FORMAT subfields like AD aren't split further here).
+fn encode_format_field(key: &str, values: &[&str]) -> Vec<u8> {
+    const TYPE_INT8: u8 = 0x1;
+
+    if key == "GT" {
+        let ploidy = values.first().map(|v| v.split(['/', '|']).count()).unwrap_or(1);
+        let mut out = typed_descriptor(TYPE_INT8, ploidy);
+        for v in values {
+            for allele in encode_gt_value(v) {
+                out.push(allele as i8 as u8);
+            }
+        }
+        return out;
+    }
+
+    if let Some(ints) = values.iter().map(|v| v.parse::<i32>().ok()).collect::<Option<Vec<_>>>() {
+        return encode_typed_int_vec(&ints);
+    }
+    if let Some(floats) = values.iter().map(|v| v.parse::<f32>().ok()).collect::<Option<Vec<_>>>() {
+        return encode_typed_float_vec(&floats);
+    }
+    encode_typed_string(&values.join(","))
+}
+
+/// Writes BCF 2.2: the `BCF\x02\x02` magic number, the text header as a
+/// length-prefixed `CString`, and typed-value records, all inside a single
+/// BGZF stream.
+struct BcfWriter<W: Write> {
+    bgzf: BgzfWriter<W>,
+}
+
+impl<W: Write> BcfWriter<W> {
+    fn new(inner: W) -> Self {
+        BcfWriter {
+            bgzf: BgzfWriter::new(inner),
+        }
+    }
+
+    fn write_header(&mut self, header: &VcfHeader) -> io::Result<()> {
+        self.bgzf.write_all(b"BCF\x02\x02")?;
+        let text_len = (header.raw.len() + 1) as u32; // + NUL terminator
+        self.bgzf.write_all(&text_len.to_le_bytes())?;
+        self.bgzf.write_all(header.raw.as_bytes())?;
+        self.bgzf.write_all(&[0u8])
+    }
+
+    fn write_record(&mut self, header: &VcfHeader, record: &Record, id: &str) -> io::Result<()> {
+        self.bgzf.write_all(&encode_bcf_record(header, record, id)?)
+    }
+
+    fn finish(self) -> io::Result<()> {
+        self.bgzf.finish()
+    }
+}
+
+/// QUAL/FILTER/INFO/[FORMAT/sample...] split out of `Record::remainder`,
+/// borrowed for the duration of `encode_bcf_record`.
+struct RecordFields<'a> {
+    qual: &'a str,
+    filter: &'a str,
+    info: &'a str,
+    format: Option<&'a str>,
+    samples: Vec<&'a str>,
+}
+
+fn split_remainder(remainder: &str) -> RecordFields<'_> {
+    let mut parts = remainder.split('\t');
+    let qual = parts.next().unwrap_or(".");
+    let filter = parts.next().unwrap_or(".");
+    let info = parts.next().unwrap_or(".");
+    let format = parts.next();
+    let samples = parts.collect();
+    RecordFields { qual, filter, info, format, samples }
+}
+
+/// Encode a single BCF record (the `l_shared`/`l_indiv`-prefixed byte
+/// layout `BcfWriter::write_record` writes) as a standalone byte buffer,
+/// so the `--threads` path can build these in parallel and have the
+/// single writer thread just concatenate them in block order. Fails if
+/// CHROM, a FILTER entry, or an INFO/FORMAT key isn't declared in the header.
+fn encode_bcf_record(header: &VcfHeader, record: &Record, id: &str) -> io::Result<Vec<u8>> {
+    let fields = split_remainder(&record.remainder);
+
+    let qual = if fields.qual == "." {
+        f32::from_bits(0x7F80_0001) // BCF missing-value bit pattern
+    } else {
+        fields.qual.parse().unwrap_or(f32::from_bits(0x7F80_0001))
+    };
+
+    let filter_indices: Vec<i32> = if fields.filter == "." {
+        Vec::new()
+    } else {
+        fields
+            .filter
+            .split(';')
+            .map(|f| header.filter_index(f).map(|i| i as i32))
+            .collect::<io::Result<Vec<_>>>()?
+    };
+
+    let mut info_bytes = Vec::new();
+    let mut n_info = 0u32;
+    if fields.info != "." {
+        for field in fields.info.split(';').filter(|f| !f.is_empty()) {
+            let (key, value) = match field.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (field, None),
+            };
+            info_bytes.extend_from_slice(&encode_typed_int(header.info_index(key)? as i32));
+            match value {
+                Some(v) => info_bytes.extend_from_slice(&encode_info_value(v)),
+                None => info_bytes.push(0x10), // Flag: type 0, count 1, no payload
+            }
+            n_info += 1;
+        }
+    }
+
+    let n_allele = 1 + record.alt.len() as u32; // REF + each ALT
+    let n_sample = fields.samples.len() as u32;
+    let n_fmt = fields.format.map(|f| f.split(':').count()).unwrap_or(0) as u32;
+
+    let mut shared = Vec::new();
+    shared.extend_from_slice(&header.contig_index(&record.chrom)?.to_le_bytes());
+    shared.extend_from_slice(&(record.pos - 1).to_le_bytes()); // 0-based POS
+    shared.extend_from_slice(&(record.ref_allele.len() as i32).to_le_bytes()); // rlen
+    shared.extend_from_slice(&qual.to_le_bytes());
+    shared.extend_from_slice(&(n_info | (n_allele << 16)).to_le_bytes());
+    shared.extend_from_slice(&(((n_fmt & 0xFF) << 24) | (n_sample & 0x00FF_FFFF)).to_le_bytes());
+    shared.extend_from_slice(&encode_typed_string(id));
+    shared.extend_from_slice(&encode_typed_string(&record.ref_allele));
+    for alt in &record.alt {
+        shared.extend_from_slice(&encode_typed_string(alt));
+    }
+    shared.extend_from_slice(&encode_typed_int_vec(&filter_indices));
+    shared.extend_from_slice(&info_bytes);
+
+    let mut indiv = Vec::new();
+    if let Some(format) = fields.format {
+        for (i, key) in format.split(':').enumerate() {
+            let values: Vec<&str> = fields
+                .samples
+                .iter()
+                .map(|s| s.split(':').nth(i).unwrap_or("."))
+                .collect();
+            indiv.extend_from_slice(&encode_typed_int(header.format_index(key)? as i32));
+            indiv.extend_from_slice(&encode_format_field(key, &values));
+        }
+    }
+
+    let mut out = Vec::with_capacity(8 + shared.len() + indiv.len());
+    out.extend_from_slice(&(shared.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(indiv.len() as u32).to_le_bytes());
+    out.extend_from_slice(&shared);
+    out.extend_from_slice(&indiv);
+    Ok(out)
+}
+
+/// A parsed VCF data line, so the ID rewrite doesn't have to hand-split the
+/// tab columns every time. `alt` is kept as a `Vec` (rather than the raw
+/// comma-joined column) since multi-allelic sites need to address each ALT
+/// allele individually.
+struct Record {
+    chrom: String,
+    pos: i32,
+    /// The ID column as read from the input, kept around for `--mode check`/`diff`.
+    id: String,
+    ref_allele: String,
+    alt: Vec<String>,
+    /// QUAL onward, already tab-joined, since the ID rewrite never touches it.
+    remainder: String,
+}
+
+/// Parse one tab-delimited VCF data line, failing cleanly instead of
+/// panicking when it has fewer than the CHROM..QUAL columns or a
+/// non-numeric POS (missing FILTER/INFO columns are read as `.` later), the same way `dict_index` fails cleanly on an
+/// undeclared header ID rather than trusting the input is well-formed.
+fn parse_record(line: &str) -> io::Result<Record> {
+    let mut splitter = line.splitn(6, '\t');
+    let mut next_column = |name: &str| {
+        splitter
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("record is missing column {name}: {line}")))
+    };
+
+    let chrom = next_column("CHROM")?.to_string();
+    let pos = next_column("POS")?
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("record has a non-numeric POS: {line}")))?;
+    let id = next_column("ID")?.to_string();
+    let ref_allele = next_column("REF")?.to_string();
+    let alt = next_column("ALT")?.split(',').map(str::to_string).collect();
+    let remainder = next_column("QUAL..")?.to_string();
+
+    Ok(Record {
+        chrom,
+        pos,
+        id,
+        ref_allele,
+        alt,
+        remainder,
+    })
+}
+
+/// Expand `record` into one record per output line: one per ALT allele
+/// when `split_multiallelic` is set (each with its own single-ALT ID), or
+/// a single record whose ID joins every ALT allele with a comma otherwise.
+/// Returns each output record alongside its normalized ID.
+fn normalize_ids(record: Record, split_multiallelic: bool) -> Vec<(Record, String)> {
+    let Record {
+        chrom,
+        pos,
+        id,
+        ref_allele,
+        alt,
+        remainder,
+    } = record;
+
+    if split_multiallelic {
+        alt.into_iter()
+            .map(|allele| {
+                let new_id = format!("{chrom}:{pos}:{ref_allele}:{allele}");
+                (
+                    Record {
+                        chrom: chrom.clone(),
+                        pos,
+                        id: id.clone(),
+                        ref_allele: ref_allele.clone(),
+                        alt: vec![allele],
+                        remainder: remainder.clone(),
+                    },
+                    new_id,
+                )
+            })
+            .collect()
+    } else {
+        let new_id = format!("{chrom}:{pos}:{ref_allele}:{}", alt.join(","));
+        vec![(
+            Record {
+                chrom,
+                pos,
+                id,
+                ref_allele,
+                alt,
+                remainder,
+            },
+            new_id,
+        )]
+    }
+}
+
+/// Render a `Record` back into a tab-delimited VCF data line with `id` in
+/// the ID column.
+fn format_vcf_line(record: &Record, id: &str) -> String {
+    format!(
+        "{}\t{}\t{id}\t{}\t{}\t{}\n",
+        record.chrom,
+        record.pos,
+        record.ref_allele,
+        record.alt.join(","),
+        record.remainder,
+    )
+}
+
+fn run(cli: Cli) -> io::Result<ExitCode> {
+    let mut reader = open_input(cli.input.as_deref())?;
+    let (header, first_data_line) = read_header(&mut reader)?;
+
+    let lines = first_data_line.into_iter().map(Ok).chain(reader.lines());
+
+    match cli.mode {
+        WriteMode::Check => {
+            let mut any_differs = false;
+            for line in lines {
+                let line = line?;
+                let record = parse_record(&line)?;
+                let original_id = record.id.clone();
+                for (_, new_id) in normalize_ids(record, cli.split_multiallelic) {
+                    if new_id != original_id {
+                        any_differs = true;
                     }
                 }
             }
-            "-o" | "--output" => {
-                // Assuming the next argument is the output file
-                if let Some(index) = args.iter().position(|a| a == arg) {
-                    if let Some(file) = args.get(index + 1) {
-                        vcf_out = Some(file.clone());
+            Ok(if any_differs { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+        }
+        WriteMode::Diff => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            // Strip a leading `/` so `a/`/`b/`-prefixed paths stay relative
+            // (an absolute `--input` would otherwise produce `a//tmp/...`,
+            // which `git apply`/`patch` reject as an invalid path).
+            let input_name = cli.input.as_deref().unwrap_or("-").trim_start_matches('/');
+            let mut header_printed = false;
+            let mut any_differs = false;
+            let mut line_no = header.raw.matches('\n').count();
+            // Line number on the rewritten side, which drifts from `line_no`
+            // once `--split-multiallelic` turns one line into several.
+            let mut new_line_no = line_no;
+
+            for line in lines {
+                let line = line?;
+                line_no += 1;
+                new_line_no += 1;
+                let record = parse_record(&line)?;
+                let rewritten = normalize_ids(record, cli.split_multiallelic);
+                let changed = rewritten.iter().any(|(r, new_id)| *new_id != r.id);
+                if changed {
+                    any_differs = true;
+                    if !header_printed {
+                        writeln!(out, "--- a/{input_name}")?;
+                        writeln!(out, "+++ b/{input_name}")?;
+                        header_printed = true;
+                    }
+                    writeln!(out, "@@ -{line_no},1 +{new_line_no},{} @@", rewritten.len())?;
+                    writeln!(out, "-{line}")?;
+                    for (record, new_id) in &rewritten {
+                        write!(out, "+{}", format_vcf_line(record, new_id))?;
                     }
                 }
+                new_line_no += rewritten.len() - 1;
             }
-            _ => {}
+
+            Ok(if any_differs { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+        }
+        WriteMode::Overwrite | WriteMode::Stdout => {
+            let output_path = if matches!(cli.mode, WriteMode::Stdout) {
+                None
+            } else {
+                cli.output.as_deref()
+            };
+            let output_format = cli
+                .output_format
+                .or_else(|| output_path.map(OutputFormat::from_path))
+                .unwrap_or(OutputFormat::Vcf);
+
+            match output_format {
+                OutputFormat::Vcf => {
+                    let gzipped = output_path.is_some_and(|p| p.ends_with(".gz"));
+                    let mut writer = OutputWriter::create(output_path, gzipped)?;
+                    write!(writer, "{}", header.raw)?;
+
+                    for line in lines {
+                        let line = line?;
+                        let record = parse_record(&line)?;
+                        for (record, id) in normalize_ids(record, cli.split_multiallelic) {
+                            writer.write_all(format_vcf_line(&record, &id).as_bytes())?;
+                        }
+                    }
+
+                    writer.finish()?;
+                }
+                OutputFormat::Bcf => {
+                    let sink: Box<dyn Write> = match output_path {
+                        Some(path) if path != "-" => Box::new(BufWriter::new(File::create(path)?)),
+                        _ => Box::new(io::stdout()),
+                    };
+                    let mut writer = BcfWriter::new(sink);
+                    writer.write_header(&header)?;
+
+                    for line in lines {
+                        let line = line?;
+                        let record = parse_record(&line)?;
+                        for (record, id) in normalize_ids(record, cli.split_multiallelic) {
+                            writer.write_record(&header, &record, &id)?;
+                        }
+                    }
+
+                    writer.finish()?;
+                }
+            }
+
+            Ok(ExitCode::SUCCESS)
         }
     }
+}
 
-    let vcf_in = vcf_in.expect("Specify input VCF with --input");
-    let vcf_out = vcf_out.expect("Specify input VCF with --input");
+/// `--threads N` entry point: loads the whole input into memory, splits it
+/// into BGZF-block-sized (or newline-chunked) pieces, rewrites each piece's
+/// records on a worker pool, and reassembles the rewritten bytes in block
+/// order before handing them to a single writer. Only covers the write
+/// modes (`check`/`diff` stay on the cheaper streaming path in `run`).
+fn run_parallel(cli: Cli) -> io::Result<ExitCode> {
+    let blocks = read_blocks(cli.input.as_deref())?;
+    let (header, data_blocks) = split_header_blocks(blocks);
 
-    // Read and process file
+    let output_path = if matches!(cli.mode, WriteMode::Stdout) {
+        None
+    } else {
+        cli.output.as_deref()
+    };
+    let output_format = cli
+        .output_format
+        .or_else(|| output_path.map(OutputFormat::from_path))
+        .unwrap_or(OutputFormat::Vcf);
+    let split_multiallelic = cli.split_multiallelic;
 
-    let input = File::open(vcf_in)?;
-    let reader = BufReader::new(input);
+    match output_format {
+        OutputFormat::Vcf => {
+            let render = move |lines: &[String]| -> io::Result<Vec<u8>> {
+                let mut out = Vec::new();
+                for line in lines {
+                    let record = parse_record(line)?;
+                    for (record, id) in normalize_ids(record, split_multiallelic) {
+                        out.extend_from_slice(format_vcf_line(&record, &id).as_bytes());
+                    }
+                }
+                Ok(out)
+            };
+            let body = render_blocks_parallel(data_blocks, cli.threads, render)?;
 
-    let output = File::create(vcf_out)?;
-    let mut writer = BufWriter::new(output);
+            let gzipped = output_path.is_some_and(|p| p.ends_with(".gz"));
+            let mut writer = OutputWriter::create(output_path, gzipped)?;
+            write!(writer, "{}", header.raw)?;
+            writer.write_all(&body)?;
+            writer.finish()?;
+        }
+        OutputFormat::Bcf => {
+            let header = Arc::new(header);
+            let render_header = Arc::clone(&header);
+            let render = move |lines: &[String]| -> io::Result<Vec<u8>> {
+                let mut out = Vec::new();
+                for line in lines {
+                    let record = parse_record(line)?;
+                    for (record, id) in normalize_ids(record, split_multiallelic) {
+                        out.extend_from_slice(&encode_bcf_record(&render_header, &record, &id)?);
+                    }
+                }
+                Ok(out)
+            };
+            let body = render_blocks_parallel(data_blocks, cli.threads, render)?;
 
-    for line in reader.lines() {
-        let line = line?;
+            let sink: Box<dyn Write> = match output_path {
+                Some(path) if path != "-" => Box::new(BufWriter::new(File::create(path)?)),
+                _ => Box::new(io::stdout()),
+            };
+            let mut writer = BcfWriter::new(sink);
+            writer.write_header(&header)?;
+            writer.bgzf.write_all(&body)?;
+            writer.finish()?;
+        }
+    }
 
-        // TODO don't have to do this check after headers are passed
-        // TODO maybe use take_until or something
-        if line.starts_with('#') {
-            writeln!(writer, "{}", line)?; //What's the diff between writeline and writer.write_all?
-            continue;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let use_parallel = cli.threads > 1 && matches!(cli.mode, WriteMode::Overwrite | WriteMode::Stdout);
+    let result = if use_parallel { run_parallel(cli) } else { run(cli) };
+
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
         }
+    }
+}
 
-        // Parse the line
-        let mut splitter = line.splitn(6, '\t');
-        let chrom = splitter.next().unwrap();
-        let pos = splitter.next().unwrap();
-        splitter.next(); // old id, not used
-        let ref_allele = splitter.next().unwrap();
-        let alt_allele = splitter.next().unwrap();
-        let remainder = splitter.next().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let id = format!("{chrom}:{pos}:{ref_allele}:{alt_allele}");
-        let out_line = format!("{chrom}\t{pos}\t{id}\t{ref_allele}\t{alt_allele}\t{remainder}\n");
+    #[test]
+    fn bgzf_round_trips_through_split_and_decompress() {
+        let mut raw = Vec::new();
+        {
+            let mut writer = BgzfWriter::new(&mut raw);
+            writer.write_all(b"##fileformat=VCFv4.2\n#CHROM\tPOS\n").unwrap();
+            writer.write_all(b"chr1\t100\n").unwrap();
+            writer.finish().unwrap();
+        }
 
-        writer.write_all(out_line.as_bytes())?;
+        let members = try_split_bgzf_members(&raw).expect("well-formed BGZF should split");
+        let decompressed: Vec<u8> = members
+            .into_iter()
+            .flat_map(|m| decompress_gzip_member(m).unwrap())
+            .collect();
+
+        assert_eq!(
+            String::from_utf8(decompressed).unwrap(),
+            "##fileformat=VCFv4.2\n#CHROM\tPOS\nchr1\t100\n"
+        );
+    }
+
+    #[test]
+    fn normalize_ids_joins_alts_unless_split_multiallelic() {
+        let record = parse_record("chr1\t100\t.\tA\tT,G\t50\tPASS\t.\n").unwrap();
+
+        let joined = normalize_ids(record, false);
+        assert_eq!(joined.len(), 1);
+        assert_eq!(joined[0].1, "chr1:100:A:T,G");
+
+        let record = parse_record("chr1\t100\t.\tA\tT,G\t50\tPASS\t.\n").unwrap();
+        let split = normalize_ids(record, true);
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[0].1, "chr1:100:A:T");
+        assert_eq!(split[1].1, "chr1:100:A:G");
     }
 
-    writer.flush()?;
-    Ok(())
+    #[test]
+    fn render_blocks_parallel_preserves_block_order() {
+        let blocks: Vec<Vec<String>> = (0..20).map(|i| vec![i.to_string()]).collect();
+
+        let render = |lines: &[String]| -> io::Result<Vec<u8>> {
+            let mut out = lines.join(",").into_bytes();
+            out.push(b'\n');
+            Ok(out)
+        };
+
+        let body = render_blocks_parallel(blocks, 4, render).unwrap();
+        let expected: String = (0..20).map(|i| format!("{i}\n")).collect();
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+    }
 }